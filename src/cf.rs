@@ -0,0 +1,219 @@
+// Copyright (C) 2024 Ethan Uppal.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3 of the License only.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Typed, safe wrappers over the CoreFoundation container types, built on
+//! [`Rc`] so that the "Create Rule" vs. "Get Rule" ownership policy is
+//! enforced by construction rather than by careful reading of `unsafe`
+//! comments: a value obtained from a `Create`/`Copy` function is owned
+//! exactly once (see [`CfString::new`], [`CfArray::from_owned`],
+//! [`CfDictionary::new`]), while a value obtained from a `Get` function must
+//! be retained before being stored in an `Rc` (see [`CfArray::from_borrowed`]).
+
+use std::{ffi::c_void, marker::PhantomData, ptr};
+
+use core_foundation_sys::{
+    array::{CFArrayGetCount, CFArrayGetValueAtIndex, CFArrayRef},
+    base::{kCFAllocatorDefault, Boolean, CFIndex},
+    dictionary::{CFDictionaryCreate, CFDictionaryRef},
+    string::{kCFStringEncodingUTF8, CFStringCreateWithBytes, CFStringRef},
+};
+
+use crate::memory::{ForeignOwnable, ManageWithRc, Rc, RcBorrow};
+
+/// A safe wrapper around an owned `CFStringRef`.
+pub struct CfString(Rc<*const c_void>);
+
+impl CfString {
+    /// Creates a `CFString` from a Rust string slice via the Create-rule
+    /// `CFStringCreateWithBytes`.
+    pub fn new(s: &str) -> Option<Self> {
+        let bytes = s.as_bytes();
+
+        // SAFETY: `bytes.as_ptr()` is valid for `bytes.len()` initialized
+        // bytes for the duration of this call.
+        let string_ref = unsafe {
+            CFStringCreateWithBytes(
+                kCFAllocatorDefault,
+                bytes.as_ptr(),
+                bytes.len() as CFIndex,
+                kCFStringEncodingUTF8,
+                false as Boolean,
+            )
+        };
+
+        // SAFETY: `CFStringCreateWithBytes` is a Create-rule API, so we own
+        // its +1 reference and `into_rc` takes it without an extra retain.
+        let rc = unsafe { (string_ref as *const c_void).into_rc() }?;
+
+        Some(Self(rc))
+    }
+
+    /// Returns the underlying `CFStringRef`, toll-free bridged with
+    /// `NSString *`.
+    ///
+    /// # Safety
+    ///
+    /// The returned pointer must not be used beyond the lifetime of `self`.
+    pub unsafe fn get(&self) -> CFStringRef {
+        // SAFETY: forwarded to the caller.
+        unsafe { self.0.get() as CFStringRef }
+    }
+}
+
+/// A safe, typed wrapper around a `CFArrayRef` whose elements are `E`.
+///
+/// `E` is only ever handed out as a borrowed [`RcBorrow<'_, E>`] from
+/// [`CfArray::iter`], since `CFArrayGetValueAtIndex` follows the Get Rule:
+/// the array owns the reference, not the caller.
+pub struct CfArray<E> {
+    rc: Rc<*const c_void>,
+    marker: PhantomData<E>,
+}
+
+impl<E> CfArray<E> {
+    /// Wraps a `CFArrayRef` already owned by the caller (e.g. returned from
+    /// a `Create`/`Copy`-rule API), taking its +1 reference via `into_rc`.
+    ///
+    /// # Safety
+    ///
+    /// `array` must be a valid, owned `CFArrayRef` whose elements are `E`.
+    pub unsafe fn from_owned(array: CFArrayRef) -> Option<Self> {
+        // SAFETY: forwarded to the caller.
+        let rc = unsafe { (array as *const c_void).into_rc() }?;
+        Some(Self {
+            rc,
+            marker: PhantomData,
+        })
+    }
+
+    /// Wraps a `CFArrayRef` owned by someone else (e.g. returned from a
+    /// `Get`-rule API), retaining it via `as_rc`.
+    ///
+    /// # Safety
+    ///
+    /// `array` must be a valid `CFArrayRef` whose elements are `E`.
+    pub unsafe fn from_borrowed(array: CFArrayRef) -> Option<Self> {
+        // SAFETY: forwarded to the caller.
+        let rc = unsafe { (array as *const c_void).as_rc() }?;
+        Some(Self {
+            rc,
+            marker: PhantomData,
+        })
+    }
+
+    /// The number of elements in the array.
+    pub fn len(&self) -> usize {
+        // SAFETY: `self.rc` is a valid `CFArrayRef` for as long as `self`
+        // lives.
+        unsafe { CFArrayGetCount(self.rc.get() as CFArrayRef) as usize }
+    }
+
+    /// Whether the array has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over the array's elements without retaining each one; see
+    /// [`RcBorrow::to_owned`] to obtain an owned handle.
+    pub fn iter(&self) -> CfArrayIter<'_, E> {
+        CfArrayIter {
+            array: self,
+            index: 0,
+        }
+    }
+}
+
+/// Iterator over a [`CfArray`]'s elements, yielded via `CFArrayGetValueAtIndex`.
+pub struct CfArrayIter<'a, E> {
+    array: &'a CfArray<E>,
+    index: usize,
+}
+
+impl<'a, E> Iterator for CfArrayIter<'a, E> {
+    type Item = RcBorrow<'a, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.array.len() {
+            return None;
+        }
+
+        // SAFETY: `self.index < self.array.len()`, and `self.array.rc` is a
+        // valid `CFArrayRef` for as long as `self.array` lives.
+        let element = unsafe {
+            CFArrayGetValueAtIndex(
+                self.array.rc.get() as CFArrayRef,
+                self.index as CFIndex,
+            )
+        };
+        self.index += 1;
+
+        // SAFETY: `CFArrayGetValueAtIndex` follows the Get Rule: the
+        // returned element is owned by the array, so it is valid for as
+        // long as `self.array` is borrowed, which matches the `'a` lifetime
+        // of this borrow.
+        Some(unsafe {
+            <Rc<E> as ForeignOwnable>::borrow(element as *const c_void)
+        })
+    }
+}
+
+/// A safe wrapper around an owned `CFDictionaryRef` whose keys are `K` and
+/// values are `V`.
+pub struct CfDictionary<K, V> {
+    rc: Rc<*const c_void>,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<K, V> CfDictionary<K, V> {
+    /// Builds a dictionary from parallel key/value arrays via the
+    /// Create-rule `CFDictionaryCreate`.
+    pub fn new(
+        keys: &[*const c_void],
+        values: &[*const c_void],
+    ) -> Option<Self> {
+        assert_eq!(keys.len(), values.len());
+
+        // SAFETY: `keys.as_ptr()` and `values.as_ptr()` are each valid
+        // pointers to `keys.len()` pointer-sized values.
+        let dict = unsafe {
+            CFDictionaryCreate(
+                kCFAllocatorDefault,
+                keys.as_ptr(),
+                values.as_ptr(),
+                keys.len() as CFIndex,
+                ptr::null(),
+                ptr::null(),
+            )
+        };
+
+        // SAFETY: `CFDictionaryCreate` is a Create-rule API, so we own its
+        // +1 reference and `into_rc` takes it without an extra retain.
+        let rc = unsafe { (dict as *const c_void).into_rc() }?;
+
+        Some(Self {
+            rc,
+            marker: PhantomData,
+        })
+    }
+
+    /// Returns the underlying `CFDictionaryRef`.
+    ///
+    /// # Safety
+    ///
+    /// The returned pointer must not be used beyond the lifetime of `self`.
+    pub unsafe fn get(&self) -> CFDictionaryRef {
+        // SAFETY: forwarded to the caller.
+        unsafe { self.rc.get() as CFDictionaryRef }
+    }
+}
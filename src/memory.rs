@@ -12,27 +12,8 @@
 // You should have received a copy of the GNU General Public License along with
 // this program.  If not, see <https://www.gnu.org/licenses/>.
 
-//pub struct Manual(pub id);
-//
-//impl Manual {
-//    pub unsafe fn retain(&mut self) {
-//        // SAFETY: responsibility of the user
-//        unsafe {
-//            CFRetain(self.0 as CFTypeRef);
-//        }
-//    }
-//
-//    pub unsafe fn release(&mut self) {
-//        // SAFETY: responsibility of the user
-//        unsafe {
-//            CFRelease(self.0 as CFTypeRef);
-//        }
-//    }
-//}
+use std::{ffi::c_void, marker::PhantomData, mem, ptr::NonNull};
 
-use std::marker::PhantomData;
-
-use cocoa::base::id;
 use core_foundation_sys::base::{
     CFGetRetainCount, CFIndex, CFRelease, CFRetain, CFTypeRef,
 };
@@ -41,7 +22,11 @@ pub struct Rc<T>(
     /// Invariant: if not all `Rc`s have been dropped, then this pointer is
     /// valid. If all `Rc`s referring to the pointer have just been
     /// dropped, then this pointer is invalid.
-    CFTypeRef,
+    ///
+    /// Stored as a `NonNull<c_void>` rather than a `CFTypeRef` so that
+    /// `Option<Rc<T>>` gets the null-pointer niche and is the same size as
+    /// `Rc<T>` itself.
+    NonNull<c_void>,
     PhantomData<T>,
 );
 
@@ -52,10 +37,54 @@ impl<T> Rc<T> {
         // By the invariant, since we have a reference to a `Rc`, not all `Rc`s
         // referring to the pointer have been dropped, so by the invariant this
         // pointer is valid.
-        unsafe { CFGetRetainCount(self.0) }
+        unsafe { CFGetRetainCount(self.0.as_ptr() as CFTypeRef) }
+    }
+
+    /// Borrows this `Rc` without touching its retain count, analogous to the
+    /// kernel's `ArcBorrow`. Unlike cloning, this performs no `CFRetain`.
+    pub fn borrow(&self) -> RcBorrow<'_, T> {
+        RcBorrow {
+            pointer: self.0,
+            marker: PhantomData,
+        }
     }
 }
 
+/// Marker for pointer types `T` that are documented by Apple as genuinely
+/// immutable CoreFoundation objects, and therefore safe to retain, release,
+/// and otherwise share across threads.
+///
+/// This must NOT be implemented for mutable Objective-C objects such as
+/// `NSRunningApplication` (`id`): those are not thread-safe, even though
+/// CoreFoundation's retain/release machinery underneath them is atomic.
+///
+/// # Safety
+///
+/// Implementing this trait for `T` asserts that every `Rc<T>` refers to an
+/// immutable CF object, so that sharing or moving it across threads is sound.
+pub unsafe trait CfThreadSafe {}
+
+// SAFETY: Immutable CoreFoundation objects are documented by Apple as safe to
+// share and pass between threads, and `T: CfThreadSafe` asserts that `T` is
+// such a type. `CFRetain`, `CFRelease`, and `CFGetRetainCount` are themselves
+// atomic, so `Clone` and `Drop` need no additional synchronization to be
+// sound across threads.
+unsafe impl<T: CfThreadSafe> Send for Rc<T> {}
+
+// SAFETY: see the `Send` impl above; sharing `&Rc<T>` across threads only
+// permits calls that are themselves backed by atomic CoreFoundation
+// operations.
+unsafe impl<T: CfThreadSafe> Sync for Rc<T> {}
+
+// SAFETY: `CfString`, `CfArray`, and `CfDictionary` (crate::cf) all store
+// their backing object as `Rc<*const c_void>`, and each of `CFString`,
+// `CFArray`, and `CFDictionary` is documented by Apple as an immutable
+// CoreFoundation container once created: mutation goes through the separate
+// `CFMutableArray`/`CFMutableDictionary` types, which are never stored this
+// way. This is the one `T` we mark thread-safe; it must not be implemented
+// for pointer types to mutable Objective-C objects such as `id`.
+unsafe impl CfThreadSafe for *const c_void {}
+
 impl<Inner> Rc<*mut Inner> {
     /// Returns `None` if the given pointer is null.
     ///
@@ -63,11 +92,7 @@ impl<Inner> Rc<*mut Inner> {
     ///
     /// `pointer` is a valid Apple API object with a nonzero retain count.
     pub unsafe fn new_mut(pointer: *mut Inner) -> Option<Self> {
-        if pointer.is_null() {
-            None
-        } else {
-            Some(Self(pointer as CFTypeRef, PhantomData))
-        }
+        Some(Self(NonNull::new(pointer as *mut c_void)?, PhantomData))
     }
 
     /// # Safety
@@ -79,7 +104,7 @@ impl<Inner> Rc<*mut Inner> {
         // all `Rc`s referring to the pointer have been dropped, so by
         // the invariant this pointer is valid. However, we leave the
         // user to responsibly use it from this call.
-        self.0 as *mut Inner
+        self.0.as_ptr() as *mut Inner
     }
 }
 
@@ -90,11 +115,10 @@ impl<Inner> Rc<*const Inner> {
     ///
     /// `pointer` is a valid Apple API object with a nonzero retain count.
     pub unsafe fn new_const(pointer: *const Inner) -> Option<Self> {
-        if pointer.is_null() {
-            None
-        } else {
-            Some(Self(pointer as CFTypeRef, PhantomData))
-        }
+        Some(Self(
+            NonNull::new(pointer as *mut c_void)?,
+            PhantomData,
+        ))
     }
 
     /// # Safety
@@ -106,7 +130,7 @@ impl<Inner> Rc<*const Inner> {
         // all `Rc`s referring to the pointer have been dropped, so by
         // the invariant this pointer is valid. However, we leave the
         // user to responsibly use it from this call.
-        self.0 as *const Inner
+        self.0.as_ptr() as *const Inner
     }
 }
 
@@ -118,7 +142,12 @@ impl<Inner> Clone for Rc<*const Inner> {
         // all `Rc`s referring to the pointer have been dropped, so by
         // the invariant this pointer is valid and we can call
         // `CFRetain` on it.
-        Self(unsafe { CFRetain(self.0) }, PhantomData)
+        let retained = unsafe { CFRetain(self.0.as_ptr() as CFTypeRef) };
+        // SAFETY: `CFRetain` returns the same, nonnull pointer it was given.
+        Self(
+            unsafe { NonNull::new_unchecked(retained as *mut c_void) },
+            PhantomData,
+        )
     }
 }
 
@@ -130,7 +159,12 @@ impl<Inner> Clone for Rc<*mut Inner> {
         // all `Rc`s referring to the pointer have been dropped, so by
         // the invariant this pointer is valid and we can call
         // `CFRetain` on it.
-        Self(unsafe { CFRetain(self.0) }, PhantomData)
+        let retained = unsafe { CFRetain(self.0.as_ptr() as CFTypeRef) };
+        // SAFETY: `CFRetain` returns the same, nonnull pointer it was given.
+        Self(
+            unsafe { NonNull::new_unchecked(retained as *mut c_void) },
+            PhantomData,
+        )
     }
 }
 
@@ -143,18 +177,148 @@ impl<T> Drop for Rc<T> {
         // the invariant this pointer is valid and we can call
         // `CFRelease` on it.
         unsafe {
-            CFRelease(self.0);
+            CFRelease(self.0.as_ptr() as CFTypeRef);
+        }
+    }
+}
+
+/// A non-owning view of an [`Rc`]'s pointer, for use inside a C callback
+/// where the retain count is not owned by this borrow.
+///
+/// See [`ForeignOwnable::borrow`] for how this is produced from a context
+/// pointer.
+pub struct RcBorrow<'a, T> {
+    pointer: NonNull<c_void>,
+    marker: PhantomData<(&'a (), T)>,
+}
+
+impl<T> RcBorrow<'_, T> {
+    /// Upgrades this borrow to a full owning [`Rc`] by calling `CFRetain`.
+    pub fn to_owned(&self) -> Rc<T> {
+        // SAFETY: by the `RcBorrow` invariant, this borrow does not outlive
+        // the foreign storage backing `self.pointer`, so `self.pointer` is
+        // still valid and we can call `CFRetain` on it.
+        let retained = unsafe { CFRetain(self.pointer.as_ptr() as CFTypeRef) };
+        // SAFETY: `CFRetain` returns the same, nonnull pointer it was given.
+        Rc(
+            unsafe { NonNull::new_unchecked(retained as *mut c_void) },
+            PhantomData,
+        )
+    }
+}
+
+/// A type that can be parked in a C/Objective-C context pointer (a "refcon")
+/// and recovered later, modeled on the Linux kernel's `ForeignOwnable`.
+///
+/// This is the mechanism by which an [`Rc`] can be handed to an API like
+/// `AXObserverAddNotification`, which takes an opaque `void*` that it hands
+/// back verbatim in a callback.
+pub trait ForeignOwnable: Sized {
+    /// The type of a non-owning view of `Self`, produced by [`Self::borrow`].
+    type Borrowed<'a>
+    where
+        Self: 'a;
+
+    /// Consumes `self` and returns the raw pointer to park in a context
+    /// pointer. The `Rc`'s retain count is preserved: this does not call
+    /// `CFRelease`, it just forgets the Rust value.
+    fn into_foreign(self) -> *const c_void;
+
+    /// Reconstitutes a value previously obtained from [`Self::into_foreign`].
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from a call to [`Self::into_foreign`], and
+    /// exactly one `from_foreign` call may be made per `into_foreign` call
+    /// that produced it.
+    unsafe fn from_foreign(ptr: *const c_void) -> Self;
+
+    /// Borrows a value previously obtained from [`Self::into_foreign`]
+    /// without taking back ownership of it, for use inside a callback where
+    /// the caller does not own the reference.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from a call to [`Self::into_foreign`] whose
+    /// matching [`Self::from_foreign`] has not yet been called, and the
+    /// returned borrow must not outlive the foreign storage holding `ptr`.
+    unsafe fn borrow<'a>(ptr: *const c_void) -> Self::Borrowed<'a>;
+}
+
+impl<T> ForeignOwnable for Rc<T> {
+    type Borrowed<'a>
+        = RcBorrow<'a, T>
+    where
+        T: 'a;
+
+    fn into_foreign(self) -> *const c_void {
+        let pointer = self.0.as_ptr() as *const c_void;
+        // We are handing off the `Rc`'s +1 reference to the foreign context
+        // pointer, so we must not run `Drop` (which would `CFRelease` it).
+        mem::forget(self);
+        pointer
+    }
+
+    unsafe fn from_foreign(ptr: *const c_void) -> Self {
+        // SAFETY: the caller guarantees `ptr` came from `into_foreign` and
+        // that this is the one `from_foreign` call claiming its +1
+        // reference, so by the `into_foreign` contract `ptr` is nonnull and
+        // valid.
+        Self(
+            unsafe { NonNull::new_unchecked(ptr as *mut c_void) },
+            PhantomData,
+        )
+    }
+
+    unsafe fn borrow<'a>(ptr: *const c_void) -> RcBorrow<'a, T> {
+        // SAFETY: the caller guarantees `ptr` came from `into_foreign` and
+        // that this borrow does not outlive the foreign storage, so `ptr`
+        // is valid for the duration of `'a`.
+        RcBorrow {
+            pointer: unsafe { NonNull::new_unchecked(ptr as *mut c_void) },
+            marker: PhantomData,
         }
     }
 }
 
-pub trait ManageWithRc {
+// Not every value parked in a context pointer is a CoreFoundation object: a
+// boxed Rust closure (e.g. an AX notification callback) needs the same
+// treatment but has no retain count to preserve, so `Box<T>` gets its own
+// impl rather than going through `Rc`.
+impl<T> ForeignOwnable for Box<T> {
+    type Borrowed<'a>
+        = &'a T
+    where
+        T: 'a;
+
+    fn into_foreign(self) -> *const c_void {
+        Box::into_raw(self) as *const c_void
+    }
+
+    unsafe fn from_foreign(ptr: *const c_void) -> Self {
+        // SAFETY: the caller guarantees `ptr` came from `into_foreign` and
+        // that this is the one `from_foreign` call reclaiming it.
+        unsafe { Box::from_raw(ptr as *mut T) }
+    }
+
+    unsafe fn borrow<'a>(ptr: *const c_void) -> &'a T {
+        // SAFETY: the caller guarantees `ptr` came from `into_foreign` and
+        // that this borrow does not outlive the foreign storage.
+        unsafe { &*(ptr as *const T) }
+    }
+}
+
+/// Implemented by the raw pointer types Apple APIs hand back, so that any of
+/// them can be wrapped in an [`Rc`] per the CoreFoundation "Create Rule"
+/// (`into_rc`, takes the existing +1 reference) or "Get Rule" (`as_rc`,
+/// retains a reference owned by someone else).
+pub trait ManageWithRc: Sized {
     /// Turn an object that you own into an [`Rc`].
     ///
     /// # Safety
     ///
     /// By using this function, you agree to the [`Rc`] invariant.
-    unsafe fn into_rc(self) -> Option<Rc<id>>;
+    unsafe fn into_rc(self) -> Option<Rc<Self>>;
 
     /// Turn an object that is already being memory-managed by another object
     /// into an [`Rc`]. Essentially, this creates a cloned `Rc`.
@@ -162,16 +326,16 @@ pub trait ManageWithRc {
     /// # Safety
     ///
     /// By using this function, you agree to the [`Rc`] invariant.
-    unsafe fn as_rc(&self) -> Option<Rc<id>>;
+    unsafe fn as_rc(&self) -> Option<Rc<Self>>;
 }
 
-impl ManageWithRc for id {
-    unsafe fn into_rc(self) -> Option<Rc<id>> {
+impl<Inner> ManageWithRc for *mut Inner {
+    unsafe fn into_rc(self) -> Option<Rc<Self>> {
         // SAFETY: user responsibility
         unsafe { Rc::new_mut(self) }
     }
 
-    unsafe fn as_rc(&self) -> Option<Rc<id>> {
+    unsafe fn as_rc(&self) -> Option<Rc<Self>> {
         // SAFETY: user responsibility
         let rc = unsafe { Rc::new_mut(*self) }?;
 
@@ -181,3 +345,20 @@ impl ManageWithRc for id {
         Some(rc)
     }
 }
+
+impl<Inner> ManageWithRc for *const Inner {
+    unsafe fn into_rc(self) -> Option<Rc<Self>> {
+        // SAFETY: user responsibility
+        unsafe { Rc::new_const(self) }
+    }
+
+    unsafe fn as_rc(&self) -> Option<Rc<Self>> {
+        // SAFETY: user responsibility
+        let rc = unsafe { Rc::new_const(*self) }?;
+
+        // SAFETY: `self` is nonnull, but the rest is user responsibility
+        unsafe { CFRetain(*self as CFTypeRef) };
+
+        Some(rc)
+    }
+}
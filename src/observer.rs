@@ -0,0 +1,206 @@
+// Copyright (C) 2024 Ethan Uppal.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3 of the License only.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A reactive layer on top of `AXObserver`, for subscribing to accessibility
+//! notifications (window moved/resized, focused-app change, window
+//! created/destroyed, ...) instead of only polling one-shot queries like
+//! [`crate::running_apps_with_bundle_id`].
+
+use std::{
+    ffi::c_void,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+use accessibility_sys::{
+    kAXErrorSuccess, AXObserverAddNotification, AXObserverCreate,
+    AXObserverGetRunLoopSource, AXObserverRef, AXUIElementCreateApplication,
+    AXUIElementRef,
+};
+use core_foundation_sys::{
+    runloop::{
+        kCFRunLoopDefaultMode, CFRunLoopAddSource, CFRunLoopGetCurrent,
+        CFRunLoopRemoveSource, CFRunLoopSourceRef,
+    },
+    string::CFStringRef,
+};
+use libc::pid_t;
+
+use crate::{
+    cf::CfString,
+    memory::{ForeignOwnable, ManageWithRc, Rc},
+    ScopeGuard, WiseError,
+};
+
+/// A callback invoked with the notified-about `AXUIElement` and the name of
+/// the notification that fired.
+type Callback = Box<dyn Fn(AXUIElementRef, CFStringRef)>;
+
+/// Wraps `AXObserverCreate`/`AXObserverAddNotification` to let callers react
+/// to live accessibility events instead of only polling.
+///
+/// Dropping an `Observer` reclaims and drops every closure it parked in a
+/// notification's refcon, matching each `into_foreign` with a
+/// `from_foreign`.
+pub struct Observer {
+    rc: Rc<AXObserverRef>,
+    // The run loop source added in `new`, removed in `Drop` before `rc`'s
+    // last reference is released.
+    run_loop_source: CFRunLoopSourceRef,
+    // Context pointers handed to `AXObserverAddNotification`, each produced
+    // by `Box<Callback>::into_foreign` and reclaimed on `Drop`.
+    registrations: Vec<*const c_void>,
+}
+
+impl Observer {
+    /// Creates an observer for the process `pid` and adds its run loop
+    /// source to the current thread's run loop in the default mode.
+    pub fn new(pid: pid_t) -> Result<Self, WiseError> {
+        let mut observer: AXObserverRef = ptr::null_mut();
+
+        // SAFETY: `&mut observer` is a valid out-pointer for one
+        // `AXObserverRef`.
+        let error = unsafe { AXObserverCreate(pid, trampoline, &mut observer) };
+        if error != kAXErrorSuccess {
+            return Err(WiseError::CouldNotCreateObserver { code: error });
+        }
+
+        // SAFETY: `AXObserverCreate` succeeded, so `observer` is a valid,
+        // owned (Create-rule) `AXObserverRef`.
+        let rc = unsafe { observer.into_rc() }
+            .ok_or(WiseError::CouldNotCreateCFObject)?;
+
+        // SAFETY: `rc` is a valid `AXObserverRef` that outlives this call.
+        let run_loop_source = unsafe { AXObserverGetRunLoopSource(rc.get()) };
+
+        // SAFETY: `CFRunLoopGetCurrent` is always valid on the calling
+        // thread, and `run_loop_source` is a valid run loop source owned by
+        // `rc`, which is kept alive for as long as `self` lives.
+        unsafe {
+            CFRunLoopAddSource(
+                CFRunLoopGetCurrent(),
+                run_loop_source,
+                kCFRunLoopDefaultMode,
+            );
+        }
+
+        Ok(Self {
+            rc,
+            run_loop_source,
+            registrations: Vec::new(),
+        })
+    }
+
+    /// Creates the root `AXUIElement` for the process `pid`.
+    pub fn application_element(
+        pid: pid_t,
+    ) -> Result<Rc<AXUIElementRef>, WiseError> {
+        // SAFETY: `AXUIElementCreateApplication` is a Create-rule API valid
+        // for any `pid`, returning a null element only on allocation
+        // failure.
+        let element = unsafe { AXUIElementCreateApplication(pid) };
+
+        // SAFETY: `element` is owned, per the Create Rule.
+        unsafe { element.into_rc() }.ok_or(WiseError::CouldNotCreateCFObject)
+    }
+
+    /// Registers `callback` to run whenever `notification` fires on
+    /// `element`.
+    pub fn add_notification(
+        &mut self,
+        element: &Rc<AXUIElementRef>,
+        notification: &str,
+        callback: impl Fn(AXUIElementRef, CFStringRef) + 'static,
+    ) -> Result<(), WiseError> {
+        let notification_cfstring = CfString::new(notification)
+            .ok_or(WiseError::CouldNotCreateCFObject)?;
+
+        let boxed: Box<Callback> = Box::new(Box::new(callback));
+
+        // `refcon` is reclaimed and dropped via `from_foreign` if we return
+        // early below, and handed to `self.registrations` via `dismiss()`
+        // only once `AXObserverAddNotification` has taken ownership of it.
+        let refcon = ScopeGuard::new(boxed.into_foreign(), |refcon| {
+            // SAFETY: `refcon` was produced by `into_foreign` above and has
+            // not been handed to `AXObserverAddNotification` successfully,
+            // so reclaiming it here is the one matching `from_foreign`.
+            let _ = unsafe { Box::<Callback>::from_foreign(refcon) };
+        });
+
+        // SAFETY: `self.rc` and `element` are valid, and `refcon` was just
+        // produced by `into_foreign` above, so it is a live context pointer
+        // for the duration of this call.
+        let error = unsafe {
+            AXObserverAddNotification(
+                self.rc.get(),
+                element.get(),
+                notification_cfstring.get() as CFStringRef,
+                *refcon as *mut c_void,
+            )
+        };
+        if error != kAXErrorSuccess {
+            return Err(WiseError::CouldNotAddNotification { code: error });
+        }
+
+        self.registrations.push(refcon.dismiss());
+        Ok(())
+    }
+}
+
+impl Drop for Observer {
+    fn drop(&mut self) {
+        // SAFETY: `self.run_loop_source` was added to the current thread's
+        // run loop in `new` and must be removed before `self.rc`'s last
+        // reference is released below, or the run loop could dereference a
+        // dangling source on its next pass.
+        unsafe {
+            CFRunLoopRemoveSource(
+                CFRunLoopGetCurrent(),
+                self.run_loop_source,
+                kCFRunLoopDefaultMode,
+            );
+        }
+
+        for refcon in self.registrations.drain(..) {
+            // SAFETY: each `refcon` was produced by exactly one
+            // `into_foreign` call in `add_notification`, and this is its
+            // matching `from_foreign` call.
+            let _ = unsafe { Box::<Callback>::from_foreign(refcon) };
+        }
+    }
+}
+
+extern "C" fn trampoline(
+    _observer: AXObserverRef,
+    element: AXUIElementRef,
+    notification: CFStringRef,
+    refcon: *mut c_void,
+) {
+    // SAFETY: `refcon` was parked by `add_notification` via
+    // `Box<Callback>::into_foreign` and this borrow does not outlive the
+    // call, well within the `Observer`'s lifetime that owns it.
+    let callback = unsafe {
+        <Box<Callback> as ForeignOwnable>::borrow(refcon as *const c_void)
+    };
+
+    // A panicking callback must not unwind across this `extern "C"`
+    // boundary into Apple's run-loop machinery, which is undefined
+    // behavior, so we catch and drop it instead.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        callback(element, notification)
+    }));
+    if result.is_err() {
+        eprintln!("wise: accessibility notification callback panicked");
+    }
+}
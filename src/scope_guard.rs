@@ -0,0 +1,79 @@
+// Copyright (C) 2024 Ethan Uppal.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3 of the License only.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+/// Runs a cleanup closure over a value when the guard is dropped, unless the
+/// guard is [`dismiss`](ScopeGuard::dismiss)ed first.
+///
+/// This is meant for manual CoreFoundation/accessibility resource juggling
+/// with `?`-based early returns: acquire a raw resource, wrap it in a
+/// `ScopeGuard` whose closure releases it, and `dismiss` the guard only once
+/// the resource has successfully been handed off (e.g. wrapped in an
+/// [`Rc`](crate::Rc)) so that any earlier `?` cleans it up automatically.
+pub struct ScopeGuard<T, F: FnOnce(T)> {
+    value: ManuallyDrop<T>,
+    cleanup: ManuallyDrop<F>,
+}
+
+impl<T, F: FnOnce(T)> ScopeGuard<T, F> {
+    /// Creates a guard that runs `cleanup` over `value` on drop.
+    pub fn new(value: T, cleanup: F) -> Self {
+        Self {
+            value: ManuallyDrop::new(value),
+            cleanup: ManuallyDrop::new(cleanup),
+        }
+    }
+
+    /// Consumes the guard and returns the inner value without running the
+    /// cleanup closure.
+    pub fn dismiss(mut self) -> T {
+        // SAFETY: `self.value` is not accessed again, and `self` is forgotten
+        // below so `Drop` never observes `self.value` or `self.cleanup` in a
+        // taken-out state.
+        let value = unsafe { ManuallyDrop::take(&mut self.value) };
+        // SAFETY: `self.cleanup` is likewise never accessed again.
+        unsafe { ManuallyDrop::drop(&mut self.cleanup) };
+        std::mem::forget(self);
+        value
+    }
+}
+
+impl<T, F: FnOnce(T)> Deref for ScopeGuard<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T, F: FnOnce(T)> DerefMut for ScopeGuard<T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T, F: FnOnce(T)> Drop for ScopeGuard<T, F> {
+    fn drop(&mut self) {
+        // SAFETY: `drop` runs at most once, so `self.value` and
+        // `self.cleanup` have not been taken out before this point.
+        let value = unsafe { ManuallyDrop::take(&mut self.value) };
+        // SAFETY: see above.
+        let cleanup = unsafe { ManuallyDrop::take(&mut self.cleanup) };
+        cleanup(value);
+    }
+}